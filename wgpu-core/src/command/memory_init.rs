@@ -1,46 +1,82 @@
-use std::{collections::hash_map::Entry, ops::Range, vec::Drain};
+use std::{collections::hash_map::Entry, ops::Range, sync::Arc, vec::Drain};
 
 use hal::CommandEncoder;
 
 use crate::{
     command::collect_zero_buffer_copies_for_clear_texture,
     device::Device,
-    hub::Storage,
-    id::{self, TextureId},
     init_tracker::*,
     resource::{Buffer, Texture},
-    track::{ResourceTracker, TextureSelector, TextureState, TrackerSet},
+    track::{PendingTransition, ResourceTracker, TextureSelector, TextureState, TrackerSet},
     FastHashMap,
 };
 
-use super::{BakedCommands, DestroyedBufferError, DestroyedTextureError};
+use super::BakedCommands;
 
 /// Surface that was discarded by `StoreOp::Discard` of a preceding renderpass.
 /// Any read access to this surface needs to be preceded by a texture initialization.
-#[derive(Clone)]
-pub(crate) struct TextureSurfaceDiscard {
-    pub texture: TextureId,
+pub(crate) struct TextureSurfaceDiscard<A: hal::Api> {
+    pub texture: Arc<Texture<A>>,
     pub mip_level: u32,
     pub layer: u32,
 }
 
-pub(crate) type SurfacesInDiscardState = Vec<TextureSurfaceDiscard>;
+// Can't `#[derive(Clone)]` without requiring `A: Clone`, which `hal::Api` isn't.
+impl<A: hal::Api> Clone for TextureSurfaceDiscard<A> {
+    fn clone(&self) -> Self {
+        Self {
+            texture: self.texture.clone(),
+            mip_level: self.mip_level,
+            layer: self.layer,
+        }
+    }
+}
+
+pub(crate) type SurfacesInDiscardState<A> = Vec<TextureSurfaceDiscard<A>>;
+
+// Per-texture accumulation of the pending transitions and zero-buffer copies needed to clear it.
+// Collecting the *owned* tracker results keyed by texture up front lets us issue a single
+// `transition_textures` for the whole command buffer and only borrow each raw texture once
+// afterwards - a naive loop would otherwise have to borrow the same `Texture` several times.
+struct TextureInitBatch<A: hal::Api> {
+    texture: Arc<Texture<A>>,
+    pending: Vec<PendingTransition<TextureState>>,
+    zero_buffer_copy_regions: Vec<hal::BufferTextureCopy>,
+}
 
-#[derive(Default)]
-pub(crate) struct CommandBufferTextureMemoryActions {
+impl<A: hal::Api> TextureInitBatch<A> {
+    fn new(texture: Arc<Texture<A>>) -> Self {
+        Self {
+            texture,
+            pending: Vec::new(),
+            zero_buffer_copy_regions: Vec::new(),
+        }
+    }
+}
+
+pub(crate) struct CommandBufferTextureMemoryActions<A: hal::Api> {
     // init actions describe the tracker actions that we need to be executed before the command buffer is executed
-    init_actions: Vec<TextureInitTrackerAction>,
+    init_actions: Vec<TextureInitTrackerAction<A>>,
     // discards describe all the discards that haven't been followed by init again within the command buffer
     // i.e. everything in this list resets the texture init state *after* the command buffer execution
-    discards: Vec<TextureSurfaceDiscard>,
+    discards: Vec<TextureSurfaceDiscard<A>>,
 }
 
-impl CommandBufferTextureMemoryActions {
-    pub(crate) fn drain_init_actions(&mut self) -> Drain<TextureInitTrackerAction> {
+impl<A: hal::Api> Default for CommandBufferTextureMemoryActions<A> {
+    fn default() -> Self {
+        Self {
+            init_actions: Default::default(),
+            discards: Default::default(),
+        }
+    }
+}
+
+impl<A: hal::Api> CommandBufferTextureMemoryActions<A> {
+    pub(crate) fn drain_init_actions(&mut self) -> Drain<TextureInitTrackerAction<A>> {
         self.init_actions.drain(..)
     }
 
-    pub(crate) fn discard(&mut self, discard: TextureSurfaceDiscard) {
+    pub(crate) fn discard(&mut self, discard: TextureSurfaceDiscard<A>) {
         self.discards.push(discard);
     }
 
@@ -48,11 +84,10 @@ impl CommandBufferTextureMemoryActions {
     // Returns previously discarded surface that need to be initialized *immediately* now.
     // Only returns a non-empty list if action is MemoryInitKind::NeedsInitializedMemory.
     #[must_use]
-    pub(crate) fn register_init_action<A: hal::Api>(
+    pub(crate) fn register_init_action(
         &mut self,
-        action: &TextureInitTrackerAction,
-        texture_guard: &Storage<Texture<A>, TextureId>,
-    ) -> SurfacesInDiscardState {
+        action: &TextureInitTrackerAction<A>,
+    ) -> SurfacesInDiscardState<A> {
         let mut immediately_necessary_clears = SurfacesInDiscardState::new();
 
         // Note that within a command buffer we may stack arbitrary memory init actions on the same texture
@@ -60,17 +95,19 @@ impl CommandBufferTextureMemoryActions {
         //
         // We don't need to add MemoryInitKind::NeedsInitializedMemory to init_actions if a surface is part of the discard list.
         // But that would mean splitting up the action which is more than we'd win here.
-        self.init_actions
-            .extend(match texture_guard.get(action.id) {
-                Ok(texture) => texture.initialization_status.check_action(action),
-                Err(_) => return immediately_necessary_clears, // texture no longer exists
-            });
+        self.init_actions.extend(
+            action
+                .texture
+                .initialization_status
+                .read()
+                .check_action(action),
+        );
 
         // We expect very few discarded surfaces at any point in time which is why a simple linear search is likely best.
         // (i.e. most of the time self.discards is empty!)
         let init_actions = &mut self.init_actions;
         self.discards.retain(|discarded_surface| {
-            if discarded_surface.texture == action.id
+            if Arc::ptr_eq(&discarded_surface.texture, &action.texture)
                 && action.range.layer_range.contains(&discarded_surface.layer)
                 && action
                     .range
@@ -82,7 +119,7 @@ impl CommandBufferTextureMemoryActions {
 
                     // Mark surface as implicitly initialized (this is relevant because it might have been uninitialized prior to discarding
                     init_actions.push(TextureInitTrackerAction {
-                        id: discarded_surface.texture,
+                        texture: discarded_surface.texture.clone(),
                         range: TextureInitRange {
                             mip_range: discarded_surface.mip_level
                                 ..(discarded_surface.mip_level + 1),
@@ -101,20 +138,16 @@ impl CommandBufferTextureMemoryActions {
     }
 
     // Shortcut for register_init_action when it is known that the action is an implicit init, not requiring any immediate resource init.
-    pub(crate) fn register_implicit_init<A: hal::Api>(
+    pub(crate) fn register_implicit_init(
         &mut self,
-        id: TextureId,
+        texture: &Arc<Texture<A>>,
         range: TextureInitRange,
-        texture_guard: &Storage<Texture<A>, TextureId>,
     ) {
-        let must_be_empty = self.register_init_action(
-            &TextureInitTrackerAction {
-                id,
-                range,
-                kind: MemoryInitKind::ImplicitlyInitialized,
-            },
-            texture_guard,
-        );
+        let must_be_empty = self.register_init_action(&TextureInitTrackerAction {
+            texture: texture.clone(),
+            range,
+            kind: MemoryInitKind::ImplicitlyInitialized,
+        });
         assert!(must_be_empty.is_empty());
     }
 }
@@ -123,23 +156,26 @@ impl CommandBufferTextureMemoryActions {
 // Takes care of barriers as well!
 pub(crate) fn fixup_discarded_surfaces<
     A: hal::Api,
-    InitIter: Iterator<Item = TextureSurfaceDiscard>,
+    InitIter: Iterator<Item = TextureSurfaceDiscard<A>>,
 >(
     inits: InitIter,
     encoder: &mut A::CommandEncoder,
-    texture_guard: &Storage<Texture<A>, TextureId>,
     texture_tracker: &mut ResourceTracker<TextureState>,
     device: &Device<A>,
 ) {
-    let mut zero_buffer_copy_regions = Vec::new();
+    // Gather all barriers and zero-buffer copies first, keyed by texture, so we can batch the
+    // transitions into a single `transition_textures` call and then emit the copies grouped per
+    // raw texture (each borrowed exactly once).
+    let mut texture_inits: FastHashMap<*const Texture<A>, TextureInitBatch<A>> =
+        FastHashMap::default();
+
     for init in inits {
         let mip_range = init.mip_level..(init.mip_level + 1);
         let layer_range = init.layer..(init.layer + 1);
 
         let (texture, pending) = texture_tracker
             .use_replace(
-                &*texture_guard,
-                init.texture,
+                init.texture.clone(),
                 TextureSelector {
                     levels: mip_range.clone(),
                     layers: layer_range.clone(),
@@ -148,24 +184,66 @@ pub(crate) fn fixup_discarded_surfaces<
             )
             .unwrap();
 
+        let batch = texture_inits
+            .entry(Arc::as_ptr(&init.texture))
+            .or_insert_with(|| TextureInitBatch::new(init.texture.clone()));
+        batch.pending.extend(pending);
         collect_zero_buffer_copies_for_clear_texture(
             &texture.desc,
             device.alignments.buffer_copy_pitch.get() as u32,
             mip_range,
             layer_range,
-            &mut zero_buffer_copy_regions,
+            &mut batch.zero_buffer_copy_regions,
+        );
+    }
+
+    emit_init_batches(texture_inits, encoder, device);
+}
+
+// Turns the per-texture barrier/copy batches gathered above into a single `transition_textures`
+// call covering every affected subresource, followed by one `copy_buffer_to_texture` per texture.
+fn emit_init_batches<A: hal::Api>(
+    mut texture_inits: FastHashMap<*const Texture<A>, TextureInitBatch<A>>,
+    encoder: &mut A::CommandEncoder,
+    device: &Device<A>,
+) {
+    let mut texture_barriers = Vec::new();
+    for batch in texture_inits.values() {
+        if batch.zero_buffer_copy_regions.is_empty() {
+            continue;
+        }
+        // A texture that reaches lazy initialization must have been created with an internal
+        // `hal_usage` that includes COPY_DST, otherwise this transition has no valid target.
+        debug_assert!(
+            batch.texture.hal_usage.contains(hal::TextureUses::COPY_DST),
+            "Texture needs to have the COPY_DST flag. Otherwise we can't ensure initialized memory!"
+        );
+        texture_barriers.extend(
+            batch
+                .pending
+                .iter()
+                .map(|pending| pending.clone().into_hal(&batch.texture)),
         );
+    }
 
-        let barriers = pending.map(|pending| pending.into_hal(texture));
-        let raw_texture = texture.inner.as_raw().unwrap();
+    // Issue the combined transition unconditionally (an empty `transition_textures` is harmless).
+    // The zero-buffer copies below are gated purely on each batch's own copy regions: a texture may
+    // still need clearing even when it already sits in `COPY_DST` in the tracker and thus produced no
+    // pending barrier.
+    unsafe {
+        encoder.transition_textures(texture_barriers.into_iter());
+    }
 
+    for batch in texture_inits.values_mut() {
+        if batch.zero_buffer_copy_regions.is_empty() {
+            continue;
+        }
+        let raw_texture = batch.texture.inner.as_raw().unwrap();
         unsafe {
-            // TODO: Should first gather all barriers, do a single transition_textures call, and then send off copy_buffer_to_texture commands.
-            encoder.transition_textures(barriers);
             encoder.copy_buffer_to_texture(
                 &device.zero_buffer,
                 raw_texture,
-                zero_buffer_copy_regions.drain(..),
+                batch.zero_buffer_copy_regions.drain(..),
             );
         }
     }
@@ -173,18 +251,14 @@ pub(crate) fn fixup_discarded_surfaces<
 
 impl<A: hal::Api> BakedCommands<A> {
     // inserts all buffer initializations that are going to be needed for executing the commands and updates resource init states accordingly
-    pub(crate) fn initialize_buffer_memory(
-        &mut self,
-        device_tracker: &mut TrackerSet,
-        buffer_guard: &mut Storage<Buffer<A>, id::BufferId>,
-    ) -> Result<(), DestroyedBufferError> {
+    pub(crate) fn initialize_buffer_memory(&mut self, device_tracker: &mut TrackerSet) {
         // Gather init ranges for each buffer so we can collapse them.
         // It is not possible to do this at an earlier point since previously executed command buffer change the resource init state.
+        //
+        // The init actions keep the buffers alive through their strong `Arc` references, so no id lookup can fail here.
         let mut uninitialized_ranges_per_buffer = FastHashMap::default();
         for buffer_use in self.buffer_memory_init_actions.drain(..) {
-            let buffer = buffer_guard
-                .get_mut(buffer_use.id)
-                .map_err(|_| DestroyedBufferError(buffer_use.id))?;
+            let mut initialization_status = buffer_use.buffer.initialization_status.write();
 
             // align the end to 4
             let end_remainder = buffer_use.range.end % wgt::COPY_BUFFER_ALIGNMENT;
@@ -193,28 +267,27 @@ impl<A: hal::Api> BakedCommands<A> {
             } else {
                 buffer_use.range.end + wgt::COPY_BUFFER_ALIGNMENT - end_remainder
             };
-            let uninitialized_ranges = buffer
-                .initialization_status
-                .drain(buffer_use.range.start..end);
+            let uninitialized_ranges = initialization_status.drain(buffer_use.range.start..end);
 
             match buffer_use.kind {
                 MemoryInitKind::ImplicitlyInitialized => {}
                 MemoryInitKind::NeedsInitializedMemory => {
-                    match uninitialized_ranges_per_buffer.entry(buffer_use.id) {
+                    match uninitialized_ranges_per_buffer.entry(Arc::as_ptr(&buffer_use.buffer)) {
                         Entry::Vacant(e) => {
-                            e.insert(
+                            e.insert((
+                                buffer_use.buffer.clone(),
                                 uninitialized_ranges.collect::<Vec<Range<wgt::BufferAddress>>>(),
-                            );
+                            ));
                         }
                         Entry::Occupied(mut e) => {
-                            e.get_mut().extend(uninitialized_ranges);
+                            e.get_mut().1.extend(uninitialized_ranges);
                         }
                     }
                 }
             }
         }
 
-        for (buffer_id, mut ranges) in uninitialized_ranges_per_buffer {
+        for (_, (buffer, mut ranges)) in uninitialized_ranges_per_buffer {
             // Collapse touching ranges.
             ranges.sort_by_key(|r| r.start);
             for i in (1..ranges.len()).rev() {
@@ -225,22 +298,18 @@ impl<A: hal::Api> BakedCommands<A> {
                 }
             }
 
-            // Don't do use_replace since the buffer may already no longer have a ref_count.
-            // However, we *know* that it is currently in use, so the tracker must already know about it.
+            // The strong reference we hold guarantees the buffer is still alive, so the tracker already knows about it.
             let transition = device_tracker.buffers.change_replace_tracked(
-                id::Valid(buffer_id),
+                &buffer,
                 (),
                 hal::BufferUses::COPY_DST,
             );
 
-            let buffer = buffer_guard
-                .get_mut(buffer_id)
-                .map_err(|_| DestroyedBufferError(buffer_id))?;
-            let raw_buf = buffer.raw.as_ref().ok_or(DestroyedBufferError(buffer_id))?;
+            let raw_buf = buffer.raw.as_ref().unwrap();
 
             unsafe {
                 self.encoder
-                    .transition_buffers(transition.map(|pending| pending.into_hal(buffer)));
+                    .transition_buffers(transition.map(|pending| pending.into_hal(&buffer)));
             }
 
             for range in ranges.iter() {
@@ -252,7 +321,6 @@ impl<A: hal::Api> BakedCommands<A> {
                 }
             }
         }
-        Ok(())
     }
 
     // inserts all texture initializations that are going to be needed for executing the commands and updates resource init states accordingly
@@ -260,18 +328,21 @@ impl<A: hal::Api> BakedCommands<A> {
     pub(crate) fn initialize_texture_memory(
         &mut self,
         device_tracker: &mut TrackerSet,
-        texture_guard: &mut Storage<Texture<A>, TextureId>,
         device: &Device<A>,
-    ) -> Result<(), DestroyedTextureError> {
+    ) {
+        // Accumulate the barriers and zero-buffer copies for every pending init across all affected
+        // textures first, keyed by texture, so the whole command buffer only needs a single
+        // `transition_textures` call (see `emit_init_batches`).
         let mut ranges: Vec<TextureInitRange> = Vec::new();
+        let mut texture_inits: FastHashMap<*const Texture<A>, TextureInitBatch<A>> =
+            FastHashMap::default();
+
         for texture_use in self.texture_memory_actions.drain_init_actions() {
-            let texture = texture_guard
-                .get_mut(texture_use.id)
-                .map_err(|_| DestroyedTextureError(texture_use.id))?;
+            let texture = &texture_use.texture;
+            let mut initialization_status = texture.initialization_status.write();
 
             let use_range = texture_use.range;
-            let affected_mip_trackers = texture
-                .initialization_status
+            let affected_mip_trackers = initialization_status
                 .mips
                 .iter_mut()
                 .enumerate()
@@ -295,68 +366,43 @@ impl<A: hal::Api> BakedCommands<A> {
                         }
                     }
 
-                    let raw_texture = texture
-                        .inner
-                        .as_raw()
-                        .ok_or(DestroyedTextureError(texture_use.id))?;
-
-                    let mut texture_barriers = Vec::new();
-                    let mut zero_buffer_copy_regions = Vec::new();
+                    let batch = texture_inits
+                        .entry(Arc::as_ptr(texture))
+                        .or_insert_with(|| TextureInitBatch::new(texture.clone()));
                     for range in &ranges {
-                        // Don't do use_replace since the texture may already no longer have a ref_count.
-                        // However, we *know* that it is currently in use, so the tracker must already know about it.
-                        texture_barriers.extend(
-                            device_tracker
-                                .textures
-                                .change_replace_tracked(
-                                    id::Valid(texture_use.id),
-                                    TextureSelector {
-                                        levels: range.mip_range.clone(),
-                                        layers: range.layer_range.clone(),
-                                    },
-                                    hal::TextureUses::COPY_DST,
-                                )
-                                .map(|pending| pending.into_hal(texture)),
-                        );
+                        // The strong reference we hold guarantees the texture is still alive, so the tracker already knows about it.
+                        // We collect the owned transitions here and only turn them into hal barriers in `emit_init_batches`,
+                        // which keeps us from borrowing the same texture mutably more than once.
+                        batch.pending.extend(device_tracker.textures.change_replace_tracked(
+                            texture,
+                            TextureSelector {
+                                levels: range.mip_range.clone(),
+                                layers: range.layer_range.clone(),
+                            },
+                            hal::TextureUses::COPY_DST,
+                        ));
 
                         collect_zero_buffer_copies_for_clear_texture(
                             &texture.desc,
                             device.alignments.buffer_copy_pitch.get() as u32,
                             range.mip_range.clone(),
                             range.layer_range.clone(),
-                            &mut zero_buffer_copy_regions,
+                            &mut batch.zero_buffer_copy_regions,
                         );
                     }
-
-                    if !zero_buffer_copy_regions.is_empty() {
-                        debug_assert!(texture.hal_usage.contains(hal::TextureUses::COPY_DST),
-                            "Texture needs to have the COPY_DST flag. Otherwise we can't ensure initialized memory!");
-                        unsafe {
-                            // TODO: Could safe on transition_textures calls by bundling barriers from *all* textures.
-                            // (a bbit more tricky because a naive approach would have to borrow same texture several times then)
-                            self.encoder
-                                .transition_textures(texture_barriers.into_iter());
-                            self.encoder.copy_buffer_to_texture(
-                                &device.zero_buffer,
-                                raw_texture,
-                                zero_buffer_copy_regions.into_iter(),
-                            );
-                        }
-                    }
                 }
             }
         }
 
+        emit_init_batches(texture_inits, &mut self.encoder, device);
+
         // Now that all buffers/textures have the proper init state for before cmdbuf start, we discard init states for textures it left discarded after its execution.
         for surface_discard in self.texture_memory_actions.discards.iter() {
-            let texture = texture_guard
-                .get_mut(surface_discard.texture)
-                .map_err(|_| DestroyedTextureError(surface_discard.texture))?;
-            texture
+            surface_discard
+                .texture
                 .initialization_status
+                .write()
                 .discard(surface_discard.mip_level, surface_discard.layer);
         }
-
-        Ok(())
     }
 }